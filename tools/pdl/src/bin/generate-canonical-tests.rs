@@ -14,19 +14,36 @@ struct Packet {
 #[derive(Debug, Deserialize)]
 struct TestVector {
     packed: String,
+    #[serde(default)]
     unpacked: Value,
     packet: Option<String>,
+    // Vectors may mark themselves as invalid instead of (or in addition
+    // to) setting `unpacked` to `null`, for inputs that are malformed in
+    // a way that still happens to parse as valid JSON.
+    #[serde(default)]
+    invalid: bool,
 }
 
 // Convert a string of hexadecimal characters into a Rust vector of
 // bytes.
 //
-// The string `"80038302"` becomes `vec![0x80, 0x03, 0x83, 0x02]`.
-fn hexadecimal_to_vec(hex: &str) -> proc_macro2::TokenStream {
-    assert!(hex.len() % 2 == 0, "Expects an even number of hex digits");
-    let bytes = hex.as_bytes().chunks_exact(2).map(|chunk| {
-        let number = format!("0x{}", std::str::from_utf8(chunk).unwrap());
-        syn::parse_str::<syn::LitInt>(&number).unwrap()
+// The string `"80038302"` becomes `vec![0x80, 0x03, 0x83, 0x02]`. An
+// optional `0x`/`0X` prefix is stripped, and whitespace and `_`, `:`,
+// `-` byte separators are ignored, so `"0x80:03-83 82_02"` is accepted
+// too. `vector_name` identifies the offending vector in panic messages.
+fn hexadecimal_to_vec(hex: &str, vector_name: &str) -> proc_macro2::TokenStream {
+    let hex = hex.strip_prefix("0x").or_else(|| hex.strip_prefix("0X")).unwrap_or(hex);
+    let digits: String =
+        hex.chars().filter(|c| !(c.is_whitespace() || matches!(c, '_' | ':' | '-'))).collect();
+    assert!(
+        digits.len() % 2 == 0,
+        "{vector_name}: expected an even number of hex digits, got {digits:?}"
+    );
+    let bytes = digits.as_bytes().chunks_exact(2).map(|chunk| {
+        let chunk = std::str::from_utf8(chunk).unwrap();
+        let number = format!("0x{chunk}");
+        syn::parse_str::<syn::LitInt>(&number)
+            .unwrap_or_else(|err| panic!("{vector_name}: invalid hex digits {chunk:?}: {err}"))
     });
 
     quote! {
@@ -34,62 +51,201 @@ fn hexadecimal_to_vec(hex: &str) -> proc_macro2::TokenStream {
     }
 }
 
-fn generate_unit_tests(input: &str, packet_names: &[&str], module_name: &str) {
-    eprintln!("Reading test vectors from {input}, will use {} packets", packet_names.len());
+// Generate an assertion for a single `unpacked` key/value pair,
+// comparing it against `accessor.get_#key()`. Arrays compare against a
+// `Vec` of integers, strings are treated as hex-encoded byte payloads,
+// and objects recurse into the nested packet returned by the getter.
+fn generate_assertion(
+    accessor: &proc_macro2::TokenStream,
+    key: &str,
+    value: &Value,
+    vector_name: &str,
+) -> proc_macro2::TokenStream {
+    let getter = format_ident!("get_{key}");
+    match value {
+        Value::Array(items) => {
+            let items = items.iter().map(|item| {
+                let n = item
+                    .as_u64()
+                    .unwrap_or_else(|| panic!("Expected u64 array element for {key:?}, got {item}"));
+                proc_macro2::Literal::u64_unsuffixed(n)
+            });
+            quote! {
+                assert_eq!(#accessor.#getter(), vec![#(#items),*]);
+            }
+        }
+        Value::String(hex) => {
+            let bytes = hexadecimal_to_vec(hex, vector_name);
+            quote! {
+                assert_eq!(#accessor.#getter(), #bytes);
+            }
+        }
+        Value::Object(nested) => {
+            let nested_accessor = quote! { #accessor.#getter() };
+            generate_assertions(&nested_accessor, nested, vector_name)
+        }
+        _ => {
+            let value_u64 =
+                value.as_u64().unwrap_or_else(|| panic!("Expected u64 for {key:?} key, got {value}"));
+            let value = proc_macro2::Literal::u64_unsuffixed(value_u64);
+            quote! {
+                assert_eq!(#accessor.#getter(), #value);
+            }
+        }
+    }
+}
+
+// Generate assertions for every key/value pair in an `unpacked` object,
+// read off of `accessor` (e.g. `actual` or a nested `actual.get_foo()`).
+fn generate_assertions(
+    accessor: &proc_macro2::TokenStream,
+    object: &serde_json::Map<String, Value>,
+    vector_name: &str,
+) -> proc_macro2::TokenStream {
+    let assertions =
+        object.iter().map(|(key, value)| generate_assertion(accessor, key, value, vector_name));
+    quote! { #(#assertions)* }
+}
+
+// Build the field initializers for a `Builder` struct literal from a
+// test vector's `unpacked` object, e.g. `field1: 1, field2: 2,`.
+fn builder_fields(object: &serde_json::Map<String, Value>) -> proc_macro2::TokenStream {
+    let fields = object.iter().map(|(key, value)| {
+        let field = format_ident!("{key}");
+        let value_u64 = value
+            .as_u64()
+            .unwrap_or_else(|| panic!("Expected u64 for {key:?} key, got {value}"));
+        let value = proc_macro2::Literal::u64_unsuffixed(value_u64);
+        quote! { #field: #value, }
+    });
+    quote! { #(#fields)* }
+}
+
+fn generate_unit_tests(
+    input: &str,
+    packet_names: &[String],
+    module_name: &str,
+    round_trip: bool,
+    output_dir: Option<&str>,
+) {
+    if packet_names.is_empty() {
+        eprintln!("Reading test vectors from {input}, will use all packets");
+    } else {
+        eprintln!("Reading test vectors from {input}, will use {} packets", packet_names.len());
+    }
 
-    let data = std::fs::read_to_string(input)
-        .unwrap_or_else(|err| panic!("Could not read {input}: {err}"));
-    let packets: Vec<Packet> = serde_json::from_str(&data).expect("Could not parse JSON");
+    let file =
+        std::fs::File::open(input).unwrap_or_else(|err| panic!("Could not open {input}: {err}"));
+    let reader = std::io::BufReader::new(file);
+    let packets: Vec<Packet> =
+        serde_json::from_reader(reader).expect("Could not parse JSON");
 
     let mut tests = Vec::new();
     for packet in &packets {
         for (i, test_vector) in packet.tests.iter().enumerate() {
             let packet_name = test_vector.packet.as_deref().unwrap_or(packet.name.as_str());
-            if !packet_names.contains(&packet_name) {
+            if !packet_names.is_empty() && !packet_names.iter().any(|name| name == packet_name) {
                 eprintln!("Skipping packet {}", packet_name);
                 continue;
             }
+            let vector_name = format!("{packet_name} vector {}", i + 1);
             let test_name =
                 format_ident!("{}_vector_{}_0x{}", packet_name, i + 1, &test_vector.packed);
-            let packed = hexadecimal_to_vec(&test_vector.packed);
-            let packet_name = format_ident!("{}Packet", packet_name);
+            let packed = hexadecimal_to_vec(&test_vector.packed, &vector_name);
+            let packet_type = format_ident!("{}Packet", packet_name);
+            let module = format_ident!("{}", module_name);
+
+            if test_vector.invalid || test_vector.unpacked.is_null() {
+                let test_name = format_ident!(
+                    "{}_vector_{}_0x{}_invalid",
+                    packet_name,
+                    i + 1,
+                    &test_vector.packed
+                );
+                tests.push(quote! {
+                    #[test]
+                    fn #test_name() {
+                        let packed = #packed;
+                        assert!(#module::#packet_type::parse(&packed).is_err());
+                    }
+                });
+                continue;
+            }
 
             let object = test_vector.unpacked.as_object().unwrap_or_else(|| {
                 panic!("Expected test vector object, found: {}", test_vector.unpacked)
             });
-            let assertions = object.iter().map(|(key, value)| {
-                let getter = format_ident!("get_{key}");
-                let value_u64 = value
-                    .as_u64()
-                    .unwrap_or_else(|| panic!("Expected u64 for {key:?} key, got {value}"));
-                let value = proc_macro2::Literal::u64_unsuffixed(value_u64);
-                quote! {
-                    assert_eq!(actual.#getter(), #value);
-                }
-            });
+            let assertions = generate_assertions(&quote! { actual }, object, &vector_name);
 
-            let module = format_ident!("{}", module_name);
             tests.push(quote! {
                 #[test]
                 fn #test_name() {
                     let packed = #packed;
-                    let actual = #module::#packet_name::parse(&packed).unwrap();
-                    #(#assertions)*
+                    let actual = #module::#packet_type::parse(&packed).unwrap();
+                    #assertions
                 }
             });
+
+            if round_trip {
+                let test_name = format_ident!(
+                    "{}_vector_{}_0x{}_round_trip",
+                    packet_name,
+                    i + 1,
+                    &test_vector.packed
+                );
+                let builder_type = format_ident!("{}Builder", packet_name);
+                let fields = builder_fields(object);
+                tests.push(quote! {
+                    #[test]
+                    fn #test_name() {
+                        let packed = #packed;
+                        let actual = #module::#builder_type { #fields }.build().to_vec();
+                        assert_eq!(actual, packed);
+                    }
+                });
+            }
         }
     }
 
     let code = quote! {
         #(#tests)*
     };
-    println!("{code}");
+    match output_dir {
+        Some(output_dir) => {
+            let output_path = std::path::Path::new(output_dir).join(format!("{module_name}.rs"));
+            std::fs::write(&output_path, code.to_string())
+                .unwrap_or_else(|err| panic!("Could not write {}: {err}", output_path.display()));
+            eprintln!("Wrote generated tests to {}", output_path.display());
+        }
+        None => println!("{code}"),
+    }
 }
 
 fn main() {
-    let input_path = std::env::args().nth(1).expect("Need path to JSON file with test vectors");
-    let module_name = std::env::args().nth(2).expect("Need name for the generated module");
-    // TODO(mgeisler): remove the `packet_names` argument when we
-    // support all canonical packets.
-    generate_unit_tests(&input_path, &["Packet_Scalar_Field"], &module_name);
+    let mut round_trip = false;
+    let mut packet_names = Vec::new();
+    let mut output_dir = None;
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--round-trip" => round_trip = true,
+            "--packets" => {
+                let value = args.next().expect("--packets needs a comma-separated list of names");
+                packet_names = value.split(',').map(str::to_string).collect();
+            }
+            "--output-dir" => {
+                output_dir = Some(args.next().expect("--output-dir needs a directory path"));
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let input_path = positional.first().expect("Need path to JSON file with test vectors");
+    let module_name = positional.get(1).expect("Need name for the generated module");
+    // By default every packet name found in the input file (either in
+    // the outer `packet` field or in a vector's `packet` override) is
+    // used. Pass `--packets` to restrict to a comma-separated subset.
+    // Without `--output-dir`, the generated code is printed to stdout.
+    generate_unit_tests(input_path, &packet_names, module_name, round_trip, output_dir.as_deref());
 }